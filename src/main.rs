@@ -13,26 +13,47 @@ use serde_json::json;
 use std::{env, sync::Arc};
 
 use log::*;
-use webrtc::api::media_engine::{MediaEngine, MIME_TYPE_H264, MIME_TYPE_VP8};
+use webrtc::api::interceptor_registry::register_default_interceptors;
+use webrtc::api::media_engine::{MediaEngine, MIME_TYPE_H264, MIME_TYPE_OPUS, MIME_TYPE_VP8};
+use webrtc::api::setting_engine::SettingEngine;
 use webrtc::api::APIBuilder;
+use webrtc::api::API;
+use webrtc::ice::network_type::NetworkType;
 use webrtc::ice_transport::ice_connection_state::RTCIceConnectionState;
 use webrtc::ice_transport::ice_server::RTCIceServer;
+use webrtc::interceptor::registry::Registry;
 use webrtc::peer_connection::configuration::RTCConfiguration;
 use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+use webrtc::peer_connection::RTCPeerConnection;
 use webrtc::rtp_transceiver::rtp_codec::{
-    RTCRtpCodecCapability, RTCRtpCodecParameters, RTPCodecType,
+    RTCPFeedback, RTCRtpCodecCapability, RTCRtpCodecParameters, RTPCodecType,
 };
+use webrtc::rtp_transceiver::rtp_transceiver_direction::RTCRtpTransceiverDirection;
+use webrtc::rtp_transceiver::RTCRtpTransceiverInit;
+use webrtc::track::track_local::track_local_static_rtp::TrackLocalStaticRTP;
 
 mod utils;
 
+const DEFAULT_WHEP_URL: &str =
+    "http://localhost:8000/api/whep?url=Zeeland&options=rtptransport%3dtcp%26timeout%3d60";
+const DEFAULT_WHIP_URL: &str = "http://localhost:8000/api/whip";
+
 #[tokio::main]
 async fn main() -> Result<()> {
-    let mut url =
-        "http://localhost:8000/api/whep?url=Zeeland&options=rtptransport%3dtcp%26timeout%3d60";
     let args: Vec<String> = env::args().collect();
-    if args.len() > 1 {
-        url = &args[1];
+    let mut idx = 1;
+    let mut whip_mode = false;
+    if args.get(1).map(String::as_str) == Some("whip") {
+        whip_mode = true;
+        idx = 2;
+    } else if args.get(1).map(String::as_str) == Some("whep") {
+        idx = 2;
     }
+    let url = args.get(idx).map(String::as_str).unwrap_or(if whip_mode {
+        DEFAULT_WHIP_URL
+    } else {
+        DEFAULT_WHEP_URL
+    });
 
     // init logger
     env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
@@ -40,57 +61,222 @@ async fn main() -> Result<()> {
     // gstreamer pipeline
     gstreamer::init()?;
 
-    // Create the API object
+    let api = build_api()?;
+
+    if whip_mode {
+        run_whip(&api, url).await
+    } else {
+        run_whep(&api, url).await
+    }
+}
+
+fn build_api() -> Result<API> {
     let mut m = MediaEngine::default();
     m.register_default_codecs()?;
-    let api = APIBuilder::new().with_media_engine(m).build();
 
-    // Prepare the configuration
-    let config = RTCConfiguration {
-        ice_servers: vec![RTCIceServer {
+    // `set_codec_preferences` only accepts codecs the engine already knows
+    // about (it fuzzy-matches against registered codecs), and the remapped
+    // H264/VP8 payload types plus the `video/rtx` codecs in
+    // `video_codec_preferences` aren't among the defaults - register them
+    // explicitly so RTX actually gets negotiated instead of filtered out.
+    for codec in video_codec_preferences() {
+        m.register_codec(codec, RTPCodecType::Video)?;
+    }
+
+    // Registering the default interceptors wires up the NACK generator: it
+    // watches the sequence numbers flowing through the receive path and emits
+    // Generic NACK feedback for the sender to fill gaps via RTX.
+    let mut registry = Registry::new();
+    registry = register_default_interceptors(registry, &mut m)?;
+
+    Ok(APIBuilder::new()
+        .with_media_engine(m)
+        .with_interceptor_registry(registry)
+        .with_setting_engine(setting_engine_from_env())
+        .build())
+}
+
+// `ICE_NETWORK_TYPES` restricts which candidate types we gather/accept, e.g.
+// "tcp4,tcp6" to force ICE-TCP on networks that block UDP.
+fn setting_engine_from_env() -> SettingEngine {
+    let mut setting_engine = SettingEngine::default();
+
+    if let Ok(val) = env::var("ICE_NETWORK_TYPES") {
+        let network_types: Vec<NetworkType> = val
+            .split(',')
+            .filter_map(|t| match t.trim() {
+                "udp4" => Some(NetworkType::Udp4),
+                "udp6" => Some(NetworkType::Udp6),
+                "tcp4" => Some(NetworkType::Tcp4),
+                "tcp6" => Some(NetworkType::Tcp6),
+                other => {
+                    warn!("ignoring unknown ICE_NETWORK_TYPES entry: {other}");
+                    None
+                }
+            })
+            .collect();
+        if !network_types.is_empty() {
+            setting_engine.set_network_types(network_types);
+        }
+    }
+
+    setting_engine
+}
+
+// `ICE_SERVERS` is a comma-separated list of STUN/TURN URLs (e.g.
+// "stun:stun.l.google.com:19302,turn:turn.example.com:3478"); `ICE_USERNAME`/
+// `ICE_CREDENTIAL` are applied to all of them, matching how a single TURN
+// account is normally shared across servers.
+fn ice_servers_from_env() -> Vec<RTCIceServer> {
+    match env::var("ICE_SERVERS") {
+        Ok(val) if !val.is_empty() => {
+            let username = env::var("ICE_USERNAME").unwrap_or_default();
+            let credential = env::var("ICE_CREDENTIAL").unwrap_or_default();
+            val.split(',')
+                .map(|url| RTCIceServer {
+                    urls: vec![url.trim().to_owned()],
+                    username: username.clone(),
+                    credential: credential.clone(),
+                    ..Default::default()
+                })
+                .collect()
+        }
+        _ => vec![RTCIceServer {
             urls: vec!["stun:stun.l.google.com:19302".to_owned()],
             ..Default::default()
         }],
+    }
+}
+
+async fn new_peer_connection(api: &API) -> Result<Arc<RTCPeerConnection>> {
+    // Prepare the configuration
+    let config = RTCConfiguration {
+        ice_servers: ice_servers_from_env(),
         ..Default::default()
     };
 
     // Create a new RTCPeerConnection
-    let peer_connection = Arc::new(api.new_peer_connection(config).await?);
+    Ok(Arc::new(api.new_peer_connection(config).await?))
+}
 
-    // Add transceiver
-    let tr = peer_connection
-        .add_transceiver_from_kind(RTPCodecType::Video, None)
-        .await?;
+fn audio_codec_preferences() -> Vec<RTCRtpCodecParameters> {
+    vec![RTCRtpCodecParameters {
+        payload_type: 111,
+        capability: RTCRtpCodecCapability {
+            mime_type: MIME_TYPE_OPUS.to_string(),
+            clock_rate: 48000,
+            channels: 2,
+            sdp_fmtp_line: "".to_owned(),
+            rtcp_feedback: vec![],
+        },
+        ..Default::default()
+    }]
+}
 
-    let payload_type = 96u8;
-    tr.set_codec_preferences(vec![
+// Each video codec is paired with a `video/rtx` codec whose `apt` fmtp parameter
+// points back at it, and advertises `nack`/`nack pli` feedback. Together with the
+// default interceptors registered in `build_api`, this lets the remote answerer
+// retransmit lost packets on the RTX payload type/SSRC; the answer SDP ties the
+// two together with `a=ssrc-group:FID <primary-ssrc> <rtx-ssrc>`, which the
+// interceptor registry unwraps back onto the primary stream before GStreamer
+// ever sees a gap.
+fn video_codec_preferences() -> Vec<RTCRtpCodecParameters> {
+    let h264_pt = 96u8;
+    let h264_rtx_pt = 97u8;
+    let vp8_pt = 98u8;
+    let vp8_rtx_pt = 99u8;
+
+    let nack_feedback = vec![
+        RTCPFeedback {
+            typ: "nack".to_owned(),
+            parameter: "".to_owned(),
+        },
+        RTCPFeedback {
+            typ: "nack".to_owned(),
+            parameter: "pli".to_owned(),
+        },
+    ];
+
+    vec![
         RTCRtpCodecParameters {
-            payload_type,
+            payload_type: h264_pt,
             capability: RTCRtpCodecCapability {
                 mime_type: MIME_TYPE_H264.to_string(),
                 clock_rate: 90000,
                 channels: 0,
                 sdp_fmtp_line: "".to_owned(),
-                rtcp_feedback: vec![],
+                rtcp_feedback: nack_feedback.clone(),
             },
             ..Default::default()
         },
+        rtx_codec_preference(h264_rtx_pt, h264_pt),
         RTCRtpCodecParameters {
-            payload_type: payload_type + 1,
+            payload_type: vp8_pt,
             capability: RTCRtpCodecCapability {
                 mime_type: MIME_TYPE_VP8.to_string(),
                 clock_rate: 90000,
                 channels: 0,
                 sdp_fmtp_line: "".to_owned(),
-                rtcp_feedback: vec![],
+                rtcp_feedback: nack_feedback,
             },
             ..Default::default()
         },
-    ])
-    .await?;
+        rtx_codec_preference(vp8_rtx_pt, vp8_pt),
+    ]
+}
+
+fn rtx_codec_preference(payload_type: u8, apt: u8) -> RTCRtpCodecParameters {
+    RTCRtpCodecParameters {
+        payload_type,
+        capability: RTCRtpCodecCapability {
+            mime_type: "video/rtx".to_owned(),
+            clock_rate: 90000,
+            channels: 0,
+            sdp_fmtp_line: format!("apt={apt}"),
+            rtcp_feedback: vec![],
+        },
+        ..Default::default()
+    }
+}
+
+// WHEP egress: pull a stream from a WHEP endpoint and play it locally.
+async fn run_whep(api: &API, url: &str) -> Result<()> {
+    let peer_connection = new_peer_connection(api).await?;
+
+    // Add video transceiver
+    let video_tr = peer_connection
+        .add_transceiver_from_kind(RTPCodecType::Video, None)
+        .await?;
+    video_tr
+        .set_codec_preferences(video_codec_preferences())
+        .await?;
+
+    // Add audio transceiver
+    let audio_tr = peer_connection
+        .add_transceiver_from_kind(RTPCodecType::Audio, None)
+        .await?;
+    audio_tr
+        .set_codec_preferences(audio_codec_preferences())
+        .await?;
+
+    // Audio and video branches are added to this same pipeline so they share a
+    // clock and stay lip-synced.
+    let pipeline = gstreamer::Pipeline::new();
+
+    // Populated once the WHEP answer is parsed, below, keyed by media type
+    // ("audio"/"video") since RFC 7273 media-clock offsets are per-stream.
+    // `on_track` only fires once the remote description is set and media
+    // starts flowing, so it's always present by the time a track handler
+    // actually runs.
+    let refclocks: Arc<std::sync::Mutex<std::collections::HashMap<String, utils::RefClock>>> =
+        Arc::new(std::sync::Mutex::new(std::collections::HashMap::new()));
 
     // Set a handler for when a new remote track starts
+    let track_pipeline = pipeline.clone();
+    let track_refclocks = refclocks.clone();
     peer_connection.on_track(Box::new(move |track, _, _| {
+        let pipeline = track_pipeline.clone();
+        let refclocks = track_refclocks.clone();
         Box::pin(async move {
             let codec: RTCRtpCodecParameters = track.codec();
             info!(
@@ -99,22 +285,21 @@ async fn main() -> Result<()> {
                 codec.capability.mime_type,
                 codec.capability.clock_rate
             );
-            let mime_type = codec.capability.mime_type.to_lowercase();
-            if mime_type == MIME_TYPE_H264.to_lowercase() {
-                info!("Got h264 track, receiving data");
+            let mime_type = codec.capability.mime_type.clone();
+            if mime_type.eq_ignore_ascii_case(MIME_TYPE_H264)
+                || mime_type.eq_ignore_ascii_case(MIME_TYPE_VP8)
+                || mime_type.eq_ignore_ascii_case(MIME_TYPE_OPUS)
+            {
+                info!("Got {mime_type} track, receiving data");
+                let media = mime_type.split('/').next().unwrap_or("").to_lowercase();
+                let refclock = refclocks.lock().unwrap().get(&media).cloned();
                 utils::create_processing(
+                    &pipeline,
                     track.payload_type(),
                     codec.capability.clock_rate,
-                    "H264",
-                    track,
-                );
-            } else if mime_type == MIME_TYPE_VP8.to_lowercase() {
-                info!("Got VP8 track, receiving data");
-                utils::create_processing(
-                    track.payload_type(),
-                    codec.capability.clock_rate,
-                    "VP8",
+                    &mime_type,
                     track,
+                    refclock,
                 );
             }
         })
@@ -124,10 +309,37 @@ async fn main() -> Result<()> {
 
     // Set the handler for ICE connection state
     // This will notify you when the peer has connected/disconnected
+    let stats_pc = Arc::downgrade(&peer_connection);
+    let stats_task: Arc<std::sync::Mutex<Option<tokio::task::JoinHandle<()>>>> =
+        Arc::new(std::sync::Mutex::new(None));
     peer_connection.on_ice_connection_state_change(Box::new(
         move |connection_state: RTCIceConnectionState| {
             info!("Connection State has changed {connection_state}");
 
+            match connection_state {
+                // The controlling offerer normally advances Connected ->
+                // Completed once ICE candidate gathering settles; treat both
+                // as "still connected" so the once-a-second quality reporter
+                // isn't killed the moment the feed stabilizes.
+                RTCIceConnectionState::Connected | RTCIceConnectionState::Completed => {
+                    let mut stats_task = stats_task.lock().unwrap();
+                    if stats_task.is_none() {
+                        if let Some(pc) = stats_pc.upgrade() {
+                            *stats_task =
+                                Some(tokio::spawn(utils::report_link_quality(pc, |_| {})));
+                        }
+                    }
+                }
+                RTCIceConnectionState::Disconnected
+                | RTCIceConnectionState::Failed
+                | RTCIceConnectionState::Closed => {
+                    if let Some(handle) = stats_task.lock().unwrap().take() {
+                        handle.abort();
+                    }
+                }
+                _ => {}
+            }
+
             if connection_state == RTCIceConnectionState::Failed {
                 let _ = done_tx.try_send(());
             }
@@ -137,7 +349,7 @@ async fn main() -> Result<()> {
 
     // Create offer
     let offer = peer_connection.create_offer(None).await?;
-    let offer_str = serde_json::to_string(&offer.sdp)?;
+    let offer_sdp = offer.sdp.clone();
 
     // Set local SessionDescription
     peer_connection.set_local_description(offer).await?;
@@ -147,10 +359,23 @@ async fn main() -> Result<()> {
     let _ = gather_complete.recv().await;
 
     // WHEP call
-    let answer_str = utils::whep(url, offer_str).await?;
-    let desc = json!({ "type": "answer", "sdp": answer_str }).to_string();
+    let session = utils::whep(url, offer_sdp).await?;
+    let desc = json!({ "type": "answer", "sdp": session.answer_sdp }).to_string();
     let answer = serde_json::from_str::<RTCSessionDescription>(&desc)?;
 
+    // RFC 7273: if the answer signals a reference clock, point the pipeline at
+    // it and remember each media type's clock offset so track handlers can
+    // map RTP timestamps to its running-time. Absent attributes fall back to
+    // the existing timestamp-free behavior.
+    let parsed_refclocks = utils::parse_refclk(&session.answer_sdp);
+    if let Some(clock) = parsed_refclocks.values().next() {
+        if let Err(e) = utils::apply_reference_clock(&pipeline, clock) {
+            warn!("failed to apply reference clock: {e}");
+        } else {
+            *refclocks.lock().unwrap() = parsed_refclocks;
+        }
+    }
+
     // Set remote SessionDescription
     peer_connection.set_remote_description(answer).await?;
 
@@ -163,6 +388,98 @@ async fn main() -> Result<()> {
         }
     };
 
+    // Release the session resource the server created for us
+    if let Some(resource_url) = session.resource_url {
+        if let Err(e) = utils::whep_delete(&resource_url).await {
+            warn!("failed to delete WHEP resource {resource_url}: {e}");
+        }
+    }
+
+    peer_connection.close().await?;
+
+    Ok(())
+}
+
+// WHIP ingest: capture/encode locally and publish to a WHIP endpoint.
+async fn run_whip(api: &API, url: &str) -> Result<()> {
+    let peer_connection = new_peer_connection(api).await?;
+
+    let payload_type = 96u8;
+    let clock_rate = 90000u32;
+    let track = Arc::new(TrackLocalStaticRTP::new(
+        RTCRtpCodecCapability {
+            mime_type: MIME_TYPE_H264.to_string(),
+            clock_rate,
+            channels: 0,
+            sdp_fmtp_line: "".to_owned(),
+            rtcp_feedback: vec![],
+        },
+        "video".to_owned(),
+        "whip-play".to_owned(),
+    ));
+
+    // Add a sendonly transceiver carrying our local track
+    let tr = peer_connection
+        .add_transceiver_from_kind(
+            RTPCodecType::Video,
+            Some(RTCRtpTransceiverInit {
+                direction: RTCRtpTransceiverDirection::Sendonly,
+                send_encodings: vec![],
+            }),
+        )
+        .await?;
+    tr.sender().await.replace_track(Some(track.clone())).await?;
+
+    utils::create_publishing(payload_type, clock_rate, "H264", track);
+
+    let (done_tx, mut done_rx) = tokio::sync::mpsc::channel::<()>(1);
+
+    peer_connection.on_ice_connection_state_change(Box::new(
+        move |connection_state: RTCIceConnectionState| {
+            info!("Connection State has changed {connection_state}");
+
+            if connection_state == RTCIceConnectionState::Failed {
+                let _ = done_tx.try_send(());
+            }
+            Box::pin(async {})
+        },
+    ));
+
+    // Create offer
+    let offer = peer_connection.create_offer(None).await?;
+    let offer_sdp = offer.sdp.clone();
+
+    // Set local SessionDescription
+    peer_connection.set_local_description(offer).await?;
+
+    // Wait ICE Gathering is complete
+    let mut gather_complete = peer_connection.gathering_complete_promise().await;
+    let _ = gather_complete.recv().await;
+
+    // WHIP call
+    let session = utils::whip(url, offer_sdp).await?;
+    let desc = json!({ "type": "answer", "sdp": session.answer_sdp }).to_string();
+    let answer = serde_json::from_str::<RTCSessionDescription>(&desc)?;
+
+    // Set remote SessionDescription
+    peer_connection.set_remote_description(answer).await?;
+
+    tokio::select! {
+        _ = done_rx.recv() => {
+            info!("received done signal!");
+        }
+        _ = tokio::signal::ctrl_c() => {
+            println!();
+        }
+    };
+
+    // Release the session resource the server created for us
+    if let Some(resource_url) = session.resource_url {
+        if let Err(e) = utils::whip_delete(&resource_url).await {
+            warn!("failed to delete WHIP resource {resource_url}: {e}");
+        }
+    }
+
     peer_connection.close().await?;
 
     Ok(())
@@ -7,42 +7,396 @@
 **
 ** -------------------------------------------------------------------------*/
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 
 use std::sync::Arc;
-use webrtc::util::Marshal;
+use webrtc::util::{Marshal, Unmarshal};
 
 use gstreamer::prelude::*;
+use gstreamer_app::AppSink;
 use log::*;
+use reqwest::header::{CONTENT_TYPE, LOCATION};
 
+use webrtc::peer_connection::RTCPeerConnection;
+use webrtc::stats::StatsReportType;
+use webrtc::track::track_local::track_local_static_rtp::TrackLocalStaticRTP;
+use webrtc::track::track_local::TrackLocalWriter;
 use webrtc::track::track_remote::TrackRemote;
 
-pub async fn whep(url: &str, offer_sdp: String) -> Result<String> {
+/// Snapshot of one inbound RTP stream's link quality, derived from the RTCP
+/// receiver reports the interceptors already collect into `get_stats()`.
+#[derive(Debug, Clone, Default)]
+pub struct LinkQuality {
+    pub ssrc: u32,
+    pub packets_lost: i64,
+    pub fraction_lost: f64,
+    pub jitter: f64,
+    pub round_trip_time: Option<f64>,
+}
+
+/// Polls `get_stats()` once a second and logs a quality line per inbound RTP
+/// stream, plus invokes `on_update` with the same data so callers can surface
+/// it some other way (e.g. a UI or metrics exporter). Runs until the task is
+/// aborted, which `main` does when the ICE connection becomes Disconnected,
+/// Failed or Closed.
+pub async fn report_link_quality(
+    peer_connection: Arc<RTCPeerConnection>,
+    on_update: impl Fn(&LinkQuality) + Send + 'static,
+) {
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(1));
+    // Per-SSRC previous cumulative counters, used to turn InboundRTP's
+    // lifetime totals into an interval loss fraction (a recvonly WHEP session
+    // never gets a RemoteInboundRTP report, since that stat describes RTCP
+    // feedback about media *we* send).
+    let mut previous: std::collections::HashMap<u32, (i64, u64)> = std::collections::HashMap::new();
+    loop {
+        interval.tick().await;
+        let report = peer_connection.get_stats().await;
+        for stat in report.reports.values() {
+            if let Some(quality) = extract_link_quality(stat, &mut previous) {
+                info!(
+                    "ssrc:{} loss:{:.2}% lost:{} jitter:{:.4} rtt:{}",
+                    quality.ssrc,
+                    quality.fraction_lost * 100.0,
+                    quality.packets_lost,
+                    quality.jitter,
+                    quality
+                        .round_trip_time
+                        .map(|rtt| format!("{rtt:.3}s"))
+                        .unwrap_or_else(|| "n/a".to_owned()),
+                );
+                on_update(&quality);
+            }
+        }
+    }
+}
+
+fn extract_link_quality(
+    stat: &StatsReportType,
+    previous: &mut std::collections::HashMap<u32, (i64, u64)>,
+) -> Option<LinkQuality> {
+    match stat {
+        // Only emitted for media we send; carries the loss/jitter/RTT the
+        // remote end computed from our RTCP sender reports.
+        StatsReportType::RemoteInboundRTP(s) => Some(LinkQuality {
+            ssrc: s.ssrc,
+            packets_lost: s.packets_lost,
+            fraction_lost: s.fraction_lost,
+            jitter: s.jitter,
+            round_trip_time: Some(s.round_trip_time),
+        }),
+        // What a recvonly WHEP session actually gets: derive the loss
+        // fraction for this polling interval from the change in cumulative
+        // lost/received counts, the same ratio RTCP receiver reports use.
+        // RTT isn't observable from this stat - it's only reported back to
+        // whichever side sends, which here is the WHEP server - so leave it
+        // unavailable instead of a hardcoded value.
+        StatsReportType::InboundRTP(s) => {
+            let packets_received = s.packets_received;
+            let (prev_lost, prev_received) = previous
+                .insert(s.ssrc, (s.packets_lost, packets_received))
+                .unwrap_or((s.packets_lost, packets_received));
+
+            let delta_lost = (s.packets_lost - prev_lost).max(0);
+            let delta_received = packets_received.saturating_sub(prev_received);
+            let expected = delta_lost as u64 + delta_received;
+            let fraction_lost = if expected > 0 {
+                delta_lost as f64 / expected as f64
+            } else {
+                0.0
+            };
+
+            Some(LinkQuality {
+                ssrc: s.ssrc,
+                packets_lost: s.packets_lost,
+                fraction_lost,
+                jitter: s.jitter,
+                round_trip_time: None,
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Result of a WHEP offer/answer exchange: the SDP answer plus, per the spec,
+/// the URL of the session resource the server created for it (from the
+/// `Location` response header), used to `DELETE` the session on teardown.
+pub struct WhepSession {
+    pub answer_sdp: String,
+    pub resource_url: Option<String>,
+}
+
+pub async fn whep(url: &str, offer_sdp: String) -> Result<WhepSession> {
+    info!("Offer:{offer_sdp}");
+    let client = reqwest::Client::new();
+    let response = client
+        .post(url)
+        .header(CONTENT_TYPE, "application/sdp")
+        .body(offer_sdp)
+        .send()
+        .await?;
+
+    ensure_sdp_answer(&response, "WHEP")?;
+
+    let resource_url = response
+        .headers()
+        .get(LOCATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|location| resolve_resource_url(url, location));
+
+    let answer_sdp = response.text().await?;
+    info!("Answer:{answer_sdp}");
+    Ok(WhepSession {
+        answer_sdp,
+        resource_url,
+    })
+}
+
+// Strictly requiring `201 Created` rejects servers that answer with a plain
+// `200 OK` (e.g. the project's own default `localhost:8000` endpoint), so
+// accept any 2xx and instead confirm the body is really an SDP answer via
+// its `Content-Type`.
+fn ensure_sdp_answer(response: &reqwest::Response, label: &str) -> Result<()> {
+    if !response.status().is_success() {
+        return Err(anyhow!("{label} server returned {}", response.status()));
+    }
+
+    let content_type = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("");
+    if content_type
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_ascii_lowercase()
+        != "application/sdp"
+    {
+        return Err(anyhow!(
+            "{label} server returned unexpected content type {content_type:?}"
+        ));
+    }
+
+    Ok(())
+}
+
+fn resolve_resource_url(whep_url: &str, location: &str) -> Option<String> {
+    reqwest::Url::parse(whep_url)
+        .ok()?
+        .join(location)
+        .ok()
+        .map(|u| u.to_string())
+}
+
+/// Tears down a WHEP session by `DELETE`-ing its resource URL, as returned in
+/// `WhepSession::resource_url`.
+pub async fn whep_delete(resource_url: &str) -> Result<()> {
+    let client = reqwest::Client::new();
+    client.delete(resource_url).send().await?;
+    Ok(())
+}
+
+/// Result of a WHIP offer/answer exchange: the SDP answer plus, per the spec,
+/// the URL of the session resource the server created for it (from the
+/// `Location` response header), used to `DELETE` the session on teardown.
+pub struct WhipSession {
+    pub answer_sdp: String,
+    pub resource_url: Option<String>,
+}
+
+pub async fn whip(url: &str, offer_sdp: String) -> Result<WhipSession> {
     info!("Offer:{offer_sdp}");
     let client = reqwest::Client::new();
-    let response = client.post(url).body(offer_sdp).send().await?;
+    let response = client
+        .post(url)
+        .header(CONTENT_TYPE, "application/sdp")
+        .body(offer_sdp)
+        .send()
+        .await?;
+
+    ensure_sdp_answer(&response, "WHIP")?;
+
+    let resource_url = response
+        .headers()
+        .get(LOCATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|location| resolve_resource_url(url, location));
+
     let answer_sdp = response.text().await?;
     info!("Answer:{answer_sdp}");
-    Ok(answer_sdp)
+    Ok(WhipSession {
+        answer_sdp,
+        resource_url,
+    })
 }
 
-pub fn create_processing(payload_type: u8, clock_rate: u32, codec: &str, track: Arc<TrackRemote>) {
-    let (pipeline, appsrc) = create_pipeline(payload_type, clock_rate, codec).unwrap();
+/// Tears down a WHIP session by `DELETE`-ing its resource URL, as returned in
+/// `WhipSession::resource_url`.
+pub async fn whip_delete(resource_url: &str) -> Result<()> {
+    let client = reqwest::Client::new();
+    client.delete(resource_url).send().await?;
+    Ok(())
+}
+
+/// RFC 7273 reference clock advertised by the answerer, parsed from
+/// `a=ts-refclk:`/`a=mediaclk:direct=` SDP attributes.
+#[derive(Debug, Clone)]
+pub enum RefClockSource {
+    Ntp { address: String },
+    Ptp { domain: u32 },
+}
+
+#[derive(Debug, Clone)]
+pub struct RefClock {
+    pub source: RefClockSource,
+    pub mediaclk_offset: i64,
+}
+
+/// Parses `a=ts-refclk:`/`a=mediaclk:direct=` attributes per `m=` section and
+/// returns one `RefClock` per media type (`"audio"`/`"video"`) whose section
+/// carries an `a=ts-refclk:`; a missing `a=mediaclk:direct=` offset is taken
+/// as 0. RFC 7273 media-clock offsets are per-stream, so a single offset
+/// applied to every track would misalign audio against video even though
+/// they commonly share the same reference clock source.
+pub fn parse_refclk(sdp: &str) -> std::collections::HashMap<String, RefClock> {
+    let mut result = std::collections::HashMap::new();
+    let mut current_media: Option<String> = None;
+    let mut source: Option<RefClockSource> = None;
+    let mut mediaclk_offset: Option<i64> = None;
+
+    for line in sdp.lines() {
+        let line = line.trim();
+        if let Some(rest) = line.strip_prefix("m=") {
+            flush_refclk_section(
+                current_media.take(),
+                source.take(),
+                mediaclk_offset.take(),
+                &mut result,
+            );
+            current_media = rest.split_whitespace().next().map(str::to_owned);
+        } else if let Some(rest) = line.strip_prefix("a=ts-refclk:") {
+            source = parse_ts_refclk(rest);
+        } else if let Some(rest) = line.strip_prefix("a=mediaclk:direct=") {
+            let rest = rest.split(' ').next().unwrap_or(rest);
+            mediaclk_offset = rest.parse::<i64>().ok();
+        }
+    }
+    flush_refclk_section(current_media, source, mediaclk_offset, &mut result);
+
+    result
+}
+
+fn flush_refclk_section(
+    media: Option<String>,
+    source: Option<RefClockSource>,
+    mediaclk_offset: Option<i64>,
+    result: &mut std::collections::HashMap<String, RefClock>,
+) {
+    if let (Some(media), Some(source)) = (media, source) {
+        result.insert(
+            media,
+            RefClock {
+                source,
+                mediaclk_offset: mediaclk_offset.unwrap_or(0),
+            },
+        );
+    }
+}
+
+fn parse_ts_refclk(value: &str) -> Option<RefClockSource> {
+    if let Some(address) = value.strip_prefix("ntp=") {
+        Some(RefClockSource::Ntp {
+            address: address.to_owned(),
+        })
+    } else if let Some(rest) = value.strip_prefix("ptp=") {
+        // "IEEE1588-2008:<gmid>:<domain>"
+        let domain = rest.rsplit(':').next()?.parse::<u32>().ok()?;
+        Some(RefClockSource::Ptp { domain })
+    } else {
+        None
+    }
+}
+
+/// Points the shared pipeline at the signalled network clock instead of the
+/// default system clock, so cross-stream (and cross-viewer) playback stays
+/// aligned to the sender's reference.
+pub fn apply_reference_clock(pipeline: &gstreamer::Pipeline, refclock: &RefClock) -> Result<()> {
+    match &refclock.source {
+        RefClockSource::Ntp { address } => {
+            let (host, port) = address.split_once(':').unwrap_or((address.as_str(), "123"));
+            let port: i32 = port.parse().unwrap_or(123);
+            info!("using NTP reference clock {host}:{port}");
+            let clock = gstreamer_net::NtpClock::new(None, host, port, gstreamer::ClockTime::ZERO);
+            pipeline.set_clock(Some(clock.upcast_ref()))?;
+        }
+        RefClockSource::Ptp { domain } => {
+            info!("using PTP reference clock domain {domain}");
+            gstreamer_net::ptp_init(None, gstreamer::ClockTime::NONE)?;
+            let clock = gstreamer_net::PtpClock::new(None, *domain)?;
+            pipeline.set_clock(Some(clock.upcast_ref()))?;
+        }
+    }
+    Ok(())
+}
+
+pub fn create_processing(
+    pipeline: &gstreamer::Pipeline,
+    payload_type: u8,
+    clock_rate: u32,
+    mimetype: &str,
+    track: Arc<TrackRemote>,
+    refclock: Option<RefClock>,
+) {
+    let appsrc = add_branch(pipeline, payload_type, clock_rate, mimetype).unwrap();
     let _ = pipeline.set_state(gstreamer::State::Playing);
 
     tokio::spawn(async move {
-        let _ = handle_data(&appsrc, track).await;
+        let _ = handle_data(&appsrc, track, clock_rate, refclock).await;
     });
 }
 
-async fn handle_data(appsrc: &gstreamer_app::AppSrc, track: Arc<TrackRemote>) -> Result<()> {
+async fn handle_data(
+    appsrc: &gstreamer_app::AppSrc,
+    track: Arc<TrackRemote>,
+    clock_rate: u32,
+    refclock: Option<RefClock>,
+) -> Result<()> {
+    // Extends the wrapping 32-bit RTP timestamp into a monotonically growing
+    // one so a buffer's running-time keeps increasing across wraparound.
+    let mut last_rtp_ts: Option<u32> = None;
+    let mut extended_ts: i64 = 0;
+
     loop {
         tokio::select! {
             result = track.read_rtp() => {
                 if let Ok((rtp_packet, _)) = result {
                     trace!("rtp:{rtp_packet}");
                     let buf = rtp_packet.marshal()?;
-                    let buffer = gstreamer::Buffer::from_slice(buf);
+                    let mut buffer = gstreamer::Buffer::from_slice(buf);
+
+                    if let Some(refclock) = &refclock {
+                        let raw_ts = rtp_packet.header.timestamp;
+                        extended_ts = match last_rtp_ts {
+                            None => raw_ts as i64,
+                            Some(prev) => extended_ts + raw_ts.wrapping_sub(prev) as i32 as i64,
+                        };
+                        last_rtp_ts = Some(raw_ts);
+
+                        let running_ts = extended_ts - refclock.mediaclk_offset;
+                        // `running_ts` keeps growing for the life of the stream (it's the
+                        // unbounded, wrap-extended RTP timestamp), so do the nanosecond
+                        // conversion in i128 - at 90 kHz, `running_ts * 1_000_000_000` alone
+                        // overflows i64 after about a day.
+                        let pts_ns = (running_ts as i128 * 1_000_000_000) / clock_rate as i128;
+                        if pts_ns >= 0 {
+                            if let Some(buffer_mut) = buffer.get_mut() {
+                                buffer_mut.set_pts(gstreamer::ClockTime::from_nseconds(pts_ns as u64));
+                            }
+                        }
+                    }
+
                     let _ = appsrc.push_buffer(buffer);
                 }else{
                     info!("read_rtp error");
@@ -53,67 +407,216 @@ async fn handle_data(appsrc: &gstreamer_app::AppSrc, track: Arc<TrackRemote>) ->
     }
 }
 
-fn create_pipeline(
+// Adds a decode branch for one track to the shared pipeline and returns its appsrc.
+// Audio and video branches live in the same pipeline so they share a clock and stay
+// lip-synced; `sync_state_with_parent` brings a branch added after the pipeline is
+// already playing up to speed.
+fn add_branch(
+    pipeline: &gstreamer::Pipeline,
     payload_type: u8,
     clock_rate: u32,
     mimetype: &str,
-) -> Result<(gstreamer::Pipeline, gstreamer_app::AppSrc)> {
+) -> Result<gstreamer_app::AppSrc> {
     let rtpdepay;
     let decoder;
     let codec;
+    let media;
 
     match mimetype {
         "video/H265" => {
             rtpdepay = "rtph265depay";
             decoder = "avdec_h265";
             codec = "H265";
+            media = "video";
         }
         "video/H264" => {
             rtpdepay = "rtph264depay";
             decoder = "avdec_h264";
             codec = "H264";
+            media = "video";
         }
         "video/VP8" => {
             rtpdepay = "rtpvp8depay";
             decoder = "avdec_vp8";
             codec = "VP8";
+            media = "video";
         }
         "video/VP9" => {
             rtpdepay = "rtpvp9depay";
             decoder = "avdec_vp9";
             codec = "VP9";
+            media = "video";
+        }
+        "audio/opus" => {
+            rtpdepay = "rtpopusdepay";
+            decoder = "opusdec";
+            codec = "OPUS";
+            media = "audio";
         }
         _ => {
             unimplemented!("mimetype:{mimetype} not managed");
         }
     }
 
-    let pipeline = gstreamer::Pipeline::new();
     let src = gstreamer::ElementFactory::make("appsrc").build()?;
     let rtp = gstreamer::ElementFactory::make(rtpdepay).build()?;
     let decode = gstreamer::ElementFactory::make(decoder).build()?;
+
+    let appsrc = configure_appsrc(src.clone(), payload_type, clock_rate, media, codec)?;
+
+    if media == "audio" {
+        let audioconvert = gstreamer::ElementFactory::make("audioconvert").build()?;
+        let audioresample = gstreamer::ElementFactory::make("audioresample").build()?;
+        let sink = gstreamer::ElementFactory::make("autoaudiosink").build()?;
+
+        pipeline.add_many(&[&src, &rtp, &decode, &audioconvert, &audioresample, &sink])?;
+        gstreamer::Element::link_many(&[
+            &src,
+            &rtp,
+            &decode,
+            &audioconvert,
+            &audioresample,
+            &sink,
+        ])?;
+        for e in [&src, &rtp, &decode, &audioconvert, &audioresample, &sink] {
+            e.sync_state_with_parent()?;
+        }
+    } else {
+        let videoconvert = gstreamer::ElementFactory::make("videoconvert").build()?;
+        let sink = gstreamer::ElementFactory::make("autovideosink").build()?;
+
+        pipeline.add_many(&[&src, &rtp, &decode, &videoconvert, &sink])?;
+        gstreamer::Element::link_many(&[&src, &rtp, &decode, &videoconvert, &sink])?;
+        for e in [&src, &rtp, &decode, &videoconvert, &sink] {
+            e.sync_state_with_parent()?;
+        }
+    }
+
+    Ok(appsrc)
+}
+
+pub fn create_publishing(
+    payload_type: u8,
+    clock_rate: u32,
+    codec: &str,
+    track: Arc<TrackLocalStaticRTP>,
+) {
+    let (pipeline, appsink) = create_publish_pipeline(payload_type, clock_rate, codec).unwrap();
+
+    // Drive the GStreamer->WebRTC hop off the `new_sample` callback instead of
+    // a blocking `pull_sample()` loop, so we don't park a tokio worker thread
+    // for the life of the stream; the callback just hands samples to the
+    // async task over a channel.
+    let (tx, rx) = tokio::sync::mpsc::channel::<gstreamer::Sample>(16);
+    appsink.set_callbacks(
+        gstreamer_app::AppSinkCallbacks::builder()
+            .new_sample(move |sink| {
+                let sample = sink.pull_sample().map_err(|_| gstreamer::FlowError::Eos)?;
+                let _ = tx.try_send(sample);
+                Ok(gstreamer::FlowSuccess::Ok)
+            })
+            .build(),
+    );
+
+    let _ = pipeline.set_state(gstreamer::State::Playing);
+
+    tokio::spawn(async move {
+        let _ = handle_publish(rx, track).await;
+    });
+}
+
+async fn handle_publish(
+    mut samples: tokio::sync::mpsc::Receiver<gstreamer::Sample>,
+    track: Arc<TrackLocalStaticRTP>,
+) -> Result<()> {
+    while let Some(sample) = samples.recv().await {
+        let Some(buffer) = sample.buffer() else {
+            continue;
+        };
+        let map = buffer.map_readable()?;
+        let mut raw = map.as_slice();
+        let rtp_packet = webrtc::rtp::packet::Packet::unmarshal(&mut raw)?;
+        trace!("rtp:{rtp_packet}");
+        track.write_rtp(&rtp_packet).await?;
+    }
+    Ok(())
+}
+
+fn create_publish_pipeline(
+    payload_type: u8,
+    clock_rate: u32,
+    mimetype: &str,
+) -> Result<(gstreamer::Pipeline, AppSink)> {
+    let encoder;
+    let rtppay;
+
+    match mimetype {
+        "H264" => {
+            encoder = "x264enc tune=zerolatency";
+            rtppay = "rtph264pay";
+        }
+        "VP8" => {
+            encoder = "vp8enc deadline=1";
+            rtppay = "rtpvp8pay";
+        }
+        _ => {
+            unimplemented!("mimetype:{mimetype} not managed");
+        }
+    }
+
+    let pipeline = gstreamer::Pipeline::new();
+    let src = gstreamer::ElementFactory::make("videotestsrc")
+        .property("is-live", true)
+        .build()?;
     let videoconvert = gstreamer::ElementFactory::make("videoconvert").build()?;
-    let sink = gstreamer::ElementFactory::make("autovideosink").build()?;
+    let enc = gstreamer::parse::bin_from_description(encoder, true)?.upcast::<gstreamer::Element>();
+    let pay = gstreamer::ElementFactory::make(rtppay)
+        .property("pt", payload_type as u32)
+        .build()?;
+    let sink = gstreamer::ElementFactory::make("appsink").build()?;
 
-    pipeline.add_many(&[&src, &rtp, &decode, &videoconvert, &sink])?;
-    gstreamer::Element::link_many(&[&src, &rtp, &decode, &videoconvert, &sink])?;
+    pipeline.add_many(&[&src, &videoconvert, &enc, &pay, &sink])?;
+    gstreamer::Element::link_many(&[&src, &videoconvert, &enc, &pay, &sink])?;
 
-    let appsrc = configure_appsrc(src, payload_type, clock_rate, codec)?;
+    let appsink = configure_appsink(sink, payload_type, clock_rate, mimetype)?;
 
-    Ok((pipeline, appsrc))
+    Ok((pipeline, appsink))
+}
+
+fn configure_appsink(
+    sink: gstreamer::Element,
+    payload_type: u8,
+    clock_rate: u32,
+    codec: &str,
+) -> Result<AppSink> {
+    let appsink = sink.dynamic_cast::<AppSink>().unwrap();
+
+    appsink.set_caps(Some(
+        &gstreamer::Caps::builder("application/x-rtp")
+            .field("media", "video")
+            .field("encoding-name", codec)
+            .field("payload", payload_type)
+            .field("clock-rate", clock_rate as i32)
+            .build(),
+    ));
+
+    info!("appsink {:?}", appsink);
+
+    Ok(appsink)
 }
 
 fn configure_appsrc(
     src: gstreamer::Element,
     payload_type: u8,
     clock_rate: u32,
+    media: &str,
     codec: &str,
 ) -> Result<gstreamer_app::AppSrc> {
     let appsrc = src.dynamic_cast::<gstreamer_app::AppSrc>().unwrap();
 
     appsrc.set_caps(Some(
         &gstreamer::Caps::builder("application/x-rtp")
-            .field("media", "video")
+            .field("media", media)
             .field("encoding-name", codec)
             .field("payload", payload_type)
             .field("clock-rate", clock_rate as i32)